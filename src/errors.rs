@@ -0,0 +1,138 @@
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Errors that can occur while locating, parsing or editing a `Cargo.toml`.
+///
+/// This replaces the old catch-all `ManifestError` with variants that carry enough context to
+/// print an actionable message: where we looked, where the parser stopped, and which section
+/// was malformed.
+#[derive(Debug)]
+pub enum CargoEditError {
+    /// No `Cargo.toml` (or, when looking for the lockfile, no `Cargo.lock`) was found between
+    /// `searched_from` and the root of the filesystem.
+    ManifestNotFound {
+        /// The directory the search started from.
+        searched_from: PathBuf,
+    },
+    /// The manifest exists but is not valid TOML.
+    InvalidToml {
+        /// 0-indexed line of the parse error.
+        line: usize,
+        /// 0-indexed column of the parse error.
+        col: usize,
+        /// The message produced by the TOML parser.
+        msg: String,
+    },
+    /// The manifest is valid TOML but has neither a `[package]` nor a `[project]` section.
+    MissingPackageSection,
+    /// A path that should lead to a dependency table instead leads to a non-table value.
+    TableIsNotATable {
+        /// The name of the table that turned out not to be one.
+        table: String,
+    },
+    /// `crates.io` has no crate by this name.
+    CrateNotFound {
+        /// The crate name that was looked up.
+        name: String,
+    },
+    /// Talking to the registry failed, or it returned something we couldn't make sense of.
+    RegistryFetchFailed {
+        /// The crate name that was being resolved.
+        name: String,
+        /// The underlying error or parse failure.
+        msg: String,
+    },
+    /// `cargo metadata` failed to run, or produced something we couldn't parse.
+    MetadataFailed {
+        /// The underlying error or parse failure.
+        msg: String,
+    },
+    /// `--package <name>` was given, but no workspace member by that name exists.
+    PackageNotFound {
+        /// The package name that was looked up.
+        name: String,
+        /// The package names that do exist in the workspace.
+        available: Vec<String>,
+    },
+    /// The discovered `Cargo.toml` is a virtual workspace manifest (`[workspace]` without a
+    /// `[package]`/`[project]`), so there's no single manifest to edit without `--package`.
+    VirtualManifest {
+        /// The package names available via `--package`.
+        members: Vec<String>,
+    },
+}
+
+impl Error for CargoEditError {
+    fn description(&self) -> &str {
+        match *self {
+            CargoEditError::ManifestNotFound { .. } => {
+                "could not find `Cargo.toml` or `Cargo.lock`"
+            }
+            CargoEditError::InvalidToml { .. } => "Cargo.toml is not valid TOML",
+            CargoEditError::MissingPackageSection => {
+                "Cargo.toml is missing a `[package]` section"
+            }
+            CargoEditError::TableIsNotATable { .. } => "not a table",
+            CargoEditError::CrateNotFound { .. } => "no such crate on crates.io",
+            CargoEditError::RegistryFetchFailed { .. } => "could not query crates.io",
+            CargoEditError::MetadataFailed { .. } => "`cargo metadata` failed",
+            CargoEditError::PackageNotFound { .. } => "no such package in this workspace",
+            CargoEditError::VirtualManifest { .. } => "Cargo.toml is a virtual workspace manifest",
+        }
+    }
+}
+
+impl fmt::Display for CargoEditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CargoEditError::ManifestNotFound { ref searched_from } => {
+                write!(f,
+                       "Could not find `Cargo.toml` (or `Cargo.lock`, when looking for the \
+                        lockfile) in `{}` or any parent directory",
+                       searched_from.display())
+            }
+            CargoEditError::InvalidToml { line, col, ref msg } => {
+                write!(f,
+                       "Invalid TOML at line {}, column {}: {}",
+                       line + 1,
+                       col + 1,
+                       msg)
+            }
+            CargoEditError::MissingPackageSection => {
+                write!(f,
+                       "Cargo.toml is missing a `[package]` (or `[project]`) section")
+            }
+            CargoEditError::TableIsNotATable { ref table } => {
+                write!(f,
+                       "The `{}` table in Cargo.toml is not a table, so a dependency can't be \
+                        added to it",
+                       table)
+            }
+            CargoEditError::CrateNotFound { ref name } => {
+                write!(f, "No crate named `{}` could be found on crates.io", name)
+            }
+            CargoEditError::RegistryFetchFailed { ref name, ref msg } => {
+                write!(f,
+                       "Failed to look up the latest version of `{}` on crates.io: {}",
+                       name,
+                       msg)
+            }
+            CargoEditError::MetadataFailed { ref msg } => {
+                write!(f, "Failed to run `cargo metadata`: {}", msg)
+            }
+            CargoEditError::PackageNotFound { ref name, ref available } => {
+                write!(f,
+                       "No package named `{}` in this workspace. Available packages: {}",
+                       name,
+                       available.join(", "))
+            }
+            CargoEditError::VirtualManifest { ref members } => {
+                write!(f,
+                       "Cargo.toml is a virtual manifest, so it has no package of its own. Use \
+                        `--package <name>` to pick one of: {}",
+                       members.join(", "))
+            }
+        }
+    }
+}
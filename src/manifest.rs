@@ -1,30 +1,19 @@
 use std::collections::BTreeMap;
-use std::{env, fmt, str};
+use std::{env, str};
 use std::error::Error;
 use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use semver;
 use toml;
 
+use errors::CargoEditError;
+use metadata;
+use registry;
+
 /// A Crate Dependency
 pub type Dependency = (String, toml::Value);
 
-#[derive(Debug)]
-/// Catch-all error for misconfigured crates.
-pub struct ManifestError;
-
-impl Error for ManifestError {
-    fn description(&self) -> &str {
-        "Your Cargo.toml is either missing or incorrectly structured."
-    }
-}
-
-impl fmt::Display for ManifestError {
-    fn fmt(&self, format: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        format.write_str(self.description())
-    }
-}
-
 enum CargoFile {
     Config,
     Lock,
@@ -35,6 +24,9 @@ enum CargoFile {
 pub struct Manifest {
     /// Manifest contents as TOML data
     pub data: toml::Table,
+    /// The original file contents, kept around so edits can be spliced into it instead of
+    /// reserializing `data` from scratch, which would alphabetise every table and drop comments.
+    raw: String,
 }
 
 /// If a manifest is specified, return that one, otherise perform a manifest search starting from
@@ -59,7 +51,11 @@ fn find(specified: &Option<&str>, file: CargoFile) -> Result<PathBuf, Box<Error>
 }
 
 /// Search for Cargo.toml in this directory and recursively up the tree until one is found.
-fn search(dir: &Path, file: CargoFile) -> Result<PathBuf, ManifestError> {
+fn search(dir: &Path, file: CargoFile) -> Result<PathBuf, CargoEditError> {
+    search_from(dir, dir, file)
+}
+
+fn search_from(start: &Path, dir: &Path, file: CargoFile) -> Result<PathBuf, CargoEditError> {
     let manifest = match file {
         CargoFile::Config => dir.join("Cargo.toml"),
         CargoFile::Lock => dir.join("Cargo.lock"),
@@ -67,7 +63,13 @@ fn search(dir: &Path, file: CargoFile) -> Result<PathBuf, ManifestError> {
 
     fs::metadata(&manifest)
         .map(|_| manifest)
-        .or(dir.parent().ok_or(ManifestError).and_then(|dir| search(dir, file)))
+        .or_else(|_| {
+            dir.parent()
+               .ok_or_else(|| {
+                   CargoEditError::ManifestNotFound { searched_from: start.to_path_buf() }
+               })
+               .and_then(|parent| search_from(start, parent, file))
+        })
 }
 
 impl Manifest {
@@ -75,14 +77,22 @@ impl Manifest {
     ///
     /// Starts at the given path an goes into its parent directories until the manifest file is
     /// found. If no path is given, the process's working directory is used as a starting point.
-    pub fn find_file(path: &Option<&str>) -> Result<File, Box<Error>> {
-        find(path, CargoFile::Config).and_then(|path| {
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(path)
-                .map_err(From::from)
-        })
+    ///
+    /// If `package` is given, the upward search is skipped entirely in favour of asking `cargo
+    /// metadata` for that workspace member's manifest, so that `cargo add --package foo` works
+    /// from anywhere inside the workspace.
+    pub fn find_file(path: &Option<&str>, package: &Option<&str>) -> Result<File, Box<Error>> {
+        let manifest_path = if let Some(name) = *package {
+            try!(metadata::locate_package(name))
+        } else {
+            try!(find(path, CargoFile::Config))
+        };
+
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(manifest_path)
+            .map_err(From::from)
     }
 
     /// Look for a `Cargo.lock` file
@@ -100,12 +110,31 @@ impl Manifest {
     }
 
     /// Open the `Cargo.toml` for a path (or the process' `cwd`)
-    pub fn open(path: &Option<&str>) -> Result<Manifest, Box<Error>> {
-        let mut file = try!(Manifest::find_file(path));
+    ///
+    /// If the manifest found turns out to be a virtual workspace manifest and no `package` was
+    /// given to disambiguate, this returns `CargoEditError::VirtualManifest` listing the members
+    /// the caller could pick from instead.
+    pub fn open(path: &Option<&str>, package: &Option<&str>) -> Result<Manifest, Box<Error>> {
+        let mut file = try!(Manifest::find_file(path, package));
         let mut data = String::new();
         try!(file.read_to_string(&mut data));
 
-        data.parse()
+        let manifest: Manifest = try!(data.parse());
+        let has_package = manifest.data.contains_key("package") ||
+                          manifest.data.contains_key("project");
+
+        if !has_package {
+            if manifest.data.contains_key("workspace") {
+                if package.is_none() {
+                    let members = try!(metadata::workspace_members());
+                    return Err(Box::new(CargoEditError::VirtualManifest { members: members }));
+                }
+            } else {
+                return Err(Box::new(CargoEditError::MissingPackageSection));
+            }
+        }
+
+        Ok(manifest)
     }
 
     /// Open the `Cargo.lock` for a path (or the process' `cwd`)
@@ -114,68 +143,331 @@ impl Manifest {
         let mut data = String::new();
         try!(file.read_to_string(&mut data));
 
-        data.parse()
+        data.parse().map_err(From::from)
     }
 
-    /// Overwrite a file with TOML data.
+    /// Overwrite a file with the manifest's contents.
+    ///
+    /// This writes out `raw`, the original document with any edits from `insert_into_table`
+    /// spliced in, rather than reserializing `data` from scratch: reserializing would alphabetise
+    /// every table and lose all comments and formatting, turning a one-line `cargo add` into a
+    /// huge, unreviewable diff.
     pub fn write_to_file<T: Seek + Write>(&self, file: &mut T) -> Result<(), Box<Error>> {
         try!(file.seek(SeekFrom::Start(0)));
-        let mut toml = self.data.clone();
-
-        let (proj_header, proj_data) = try!(toml.remove("package")
-                                                .map(|data| ("package", data))
-                                                .or_else(|| {
-                                                    toml.remove("project")
-                                                        .map(|data| ("project", data))
-                                                })
-                                                .ok_or(ManifestError));
-        write!(file,
-               "[{}]\n{}{}",
-               proj_header,
-               proj_data,
-               toml::Value::Table(toml))
-            .map_err(From::from)
+        file.write_all(self.raw.as_bytes()).map_err(From::from)
     }
 
-    /// Add entry to a Cargo.toml.
+    /// Add entry to a Cargo.toml, creating any intermediate tables along `table_path` that don't
+    /// already exist (e.g. `["target", "cfg(windows)", "dependencies"]`).
+    ///
+    /// Updates `data` as before, but also splices the new `name = ...` line directly into `raw`
+    /// under the relevant table header (creating the header if it's missing), so that everything
+    /// else in the document is left byte-for-byte untouched.
     #[cfg_attr(feature = "dev", allow(toplevel_ref_arg))]
     pub fn insert_into_table(&mut self,
-                             table: &str,
+                             table_path: &[String],
                              &(ref name, ref data): &Dependency)
-                             -> Result<(), ManifestError> {
-        let ref mut manifest = self.data;
-        let entry = manifest.entry(String::from(table))
-                            .or_insert(toml::Value::Table(BTreeMap::new()));
-        match *entry {
-            toml::Value::Table(ref mut deps) => {
-                deps.insert(name.clone(), data.clone());
-                Ok(())
+                             -> Result<(), CargoEditError> {
+        {
+            let table = try!(Manifest::get_table(&mut self.data, table_path));
+
+            match *table {
+                toml::Value::Table(ref mut deps) => {
+                    deps.insert(name.clone(), data.clone());
+                }
+                _ => unreachable!("get_table never returns a non-table entry"),
+            }
+        }
+
+        let header = table_header(table_path);
+        let line = format!("{} = {}\n", name, data);
+        splice_entry(&mut self.raw, &header, name, &line);
+
+        Ok(())
+    }
+
+    /// Walk `table_path` from the root of `data`, creating any missing tables along the way, and
+    /// return the leaf. Errors if an existing non-leaf segment isn't itself a table.
+    fn get_table<'a>(data: &'a mut toml::Table,
+                     table_path: &[String])
+                     -> Result<&'a mut toml::Value, CargoEditError> {
+        let (head, rest) = table_path.split_first()
+                                     .expect("table_path must have at least one segment");
+
+        let entry = data.entry(head.clone())
+                        .or_insert_with(|| toml::Value::Table(BTreeMap::new()));
+
+        if rest.is_empty() {
+            match *entry {
+                toml::Value::Table(_) => Ok(entry),
+                _ => Err(CargoEditError::TableIsNotATable { table: head.clone() }),
+            }
+        } else {
+            match *entry {
+                toml::Value::Table(ref mut nested) => Manifest::get_table(nested, rest),
+                _ => Err(CargoEditError::TableIsNotATable { table: head.clone() }),
             }
-            _ => Err(ManifestError),
         }
     }
 
     /// Add multiple dependencies to manifest
-    pub fn add_deps(&mut self, table: &str, deps: &[Dependency]) -> Result<(), Box<Error>> {
+    ///
+    /// A dependency whose version requirement is the empty string (the placeholder a caller
+    /// uses for "no version given on the command line") is resolved before being inserted:
+    /// `Cargo.lock` is checked first, and if it has no entry for the crate either, the latest
+    /// version is fetched from crates.io unless `offline` (`--no-fetch`) is set. Pass `None` for
+    /// `lockfile` when there's no `Cargo.lock` to consult.
+    pub fn add_deps(&mut self,
+                    table_path: &[String],
+                    deps: &[Dependency],
+                    lockfile: Option<&Manifest>,
+                    offline: bool)
+                    -> Result<(), Box<Error>> {
         deps.iter()
-            .map(|dep| self.insert_into_table(table, &dep))
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(From::from)
+            .map(|dep| {
+                let resolved = try!(resolve_version(dep, lockfile, offline));
+                self.insert_into_table(table_path, &resolved).map_err(From::from)
+            })
+            .collect::<Result<Vec<_>, Box<Error>>>()
             .map(|_| ())
     }
+
+    /// Find the version of `name` that was resolved in a `Cargo.lock`.
+    ///
+    /// `self` is expected to be the `Manifest` returned by `Manifest::open_lock_file`. If the
+    /// lockfile records more than one entry for `name` (legitimate once two major versions of a
+    /// crate are both present in the dependency graph), the highest version wins. Returns `None`
+    /// if the crate isn't in the lockfile at all, so callers can fall back to their own default.
+    pub fn find_locked_version(&self, name: &str) -> Option<semver::Version> {
+        let packages = match self.data.get("package") {
+            Some(&toml::Value::Array(ref packages)) => packages,
+            _ => return None,
+        };
+
+        packages.iter()
+            .filter_map(|package| match *package {
+                toml::Value::Table(ref package) => Some(package),
+                _ => None,
+            })
+            .filter(|package| match package.get("name") {
+                Some(&toml::Value::String(ref pkg_name)) => pkg_name == name,
+                _ => false,
+            })
+            .filter_map(|package| match package.get("version") {
+                Some(&toml::Value::String(ref version)) => semver::Version::parse(version).ok(),
+                _ => None,
+            })
+            .max()
+    }
+}
+
+/// Render `table_path` as the TOML header it would appear under, e.g. `["target",
+/// "cfg(windows)", "dependencies"]` becomes `[target.'cfg(windows)'.dependencies]`.
+fn table_header(table_path: &[String]) -> String {
+    let segment = |s: &String| if is_bare_key(s) {
+        s.clone()
+    } else {
+        format!("'{}'", s)
+    };
+
+    format!("[{}]",
+            table_path.iter().map(segment).collect::<Vec<_>>().join("."))
+}
+
+/// `true` if `s` can appear in a dotted TOML key path without quoting.
+fn is_bare_key(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Return the byte offset of the start of the first line of `s` (searching from byte offset
+/// `from`, which must itself be the start of a line) for which `pred` holds.
+fn find_line_from<F: Fn(&str) -> bool>(s: &str, from: usize, pred: F) -> Option<usize> {
+    let mut pos = from;
+    for line in s[from..].split('\n') {
+        if pred(line) {
+            return Some(pos);
+        }
+        pos += line.len() + 1;
+    }
+    None
+}
+
+/// Find the byte range (including its trailing newline, if it has one) of the line declaring
+/// `name` inside `section`, e.g. to replace `foo = "1"` in place when `foo` is re-added.
+fn find_entry_line(section: &str, name: &str) -> Option<(usize, usize)> {
+    let mut pos = 0;
+    for line in section.split('\n') {
+        if let Some(eq) = line.find('=') {
+            if line[..eq].trim() == name {
+                let end = pos + line.len();
+                let end = if end < section.len() { end + 1 } else { end };
+                return Some((pos, end));
+            }
+        }
+        pos += line.len() + 1;
+    }
+    None
+}
+
+/// Splice `line` (an already-formatted `name = value\n` entry) into `raw` under `header`,
+/// replacing the existing entry for `name` in that table if one exists rather than appending a
+/// second one (which would otherwise produce a Cargo.toml with a duplicate key). Creates the
+/// header, and its own section, if it isn't present yet.
+///
+/// The header is matched only against a whole line (surrounding whitespace aside), so a
+/// `[dependencies]` that follows other text on its line, as in a comment or a single-line string
+/// value, isn't mistaken for the table header. A header line that carries a trailing comment is
+/// not recognised, and a line of a multi-line string that holds only the header is taken for it.
+fn splice_entry(raw: &mut String, header: &str, name: &str, line: &str) {
+    let header_start = match find_line_from(raw, 0, |l| l.trim() == header) {
+        Some(pos) => pos,
+        None => {
+            if !raw.is_empty() && !raw.ends_with('\n') {
+                raw.push('\n');
+            }
+            if !raw.is_empty() {
+                raw.push('\n');
+            }
+            raw.push_str(header);
+            raw.push('\n');
+            raw.push_str(line);
+            return;
+        }
+    };
+
+    let section_start = match raw[header_start..].find('\n') {
+        Some(i) => header_start + i + 1,
+        None => {
+            // The header is the very last line in the file and has no trailing newline yet;
+            // give it one so the spliced entry lands on its own line instead of being appended
+            // to the header line itself.
+            raw.push('\n');
+            raw.len()
+        }
+    };
+    let section_end = find_line_from(raw, section_start, |l| l.trim_start().starts_with('['))
+                          .unwrap_or_else(|| raw.len());
+
+    if let Some((rel_start, rel_end)) = find_entry_line(&raw[section_start..section_end], name) {
+        let entry_start = section_start + rel_start;
+        let entry_end = section_start + rel_end;
+        raw.drain(entry_start..entry_end);
+        raw.insert_str(entry_start, line);
+    } else {
+        let needs_newline = section_end > section_start && !raw[..section_end].ends_with('\n');
+        let mut insertion = String::with_capacity(line.len() + 1);
+        if needs_newline {
+            insertion.push('\n');
+        }
+        insertion.push_str(line);
+        raw.insert_str(section_end, &insertion);
+    }
+}
+
+/// Build the table path a dependency should be inserted into, given the `--dev`, `--build` and
+/// `--target <spec>` flags.
+///
+/// `--dev`/`--build` select `dev-dependencies`/`build-dependencies` instead of the default
+/// `dependencies`; `--target` nests whichever of those under `[target.<spec>]`, matching how
+/// `Cargo.toml` itself expresses target-specific dependencies.
+pub fn dependency_table_path(target: &Option<&str>, dev: bool, build: bool) -> Vec<String> {
+    let section = if dev {
+        "dev-dependencies"
+    } else if build {
+        "build-dependencies"
+    } else {
+        "dependencies"
+    };
+
+    match *target {
+        Some(spec) => vec!["target".to_owned(), spec.to_owned(), section.to_owned()],
+        None => vec![section.to_owned()],
+    }
+}
+
+/// Turn a resolved lockfile version into the caret requirement `cargo add` writes to
+/// `Cargo.toml` (e.g. `1.2.3` becomes `^1.2.3`).
+pub fn caret_requirement(version: &semver::Version) -> String {
+    format!("^{}", version)
+}
+
+/// `true` if `dep`'s requirement is the empty-string placeholder for "no version given".
+fn needs_version(dep: &Dependency) -> bool {
+    match dep.1 {
+        toml::Value::String(ref req) => req.is_empty(),
+        _ => false,
+    }
+}
+
+/// Resolve `dep`'s version requirement when none was given on the command line.
+///
+/// Used by `add_deps` as the version-resolution step ahead of building the `Dependency` tuple it
+/// hands to `insert_into_table`. Precedence matches `cargo add`: a version the user actually
+/// typed wins outright (this is then a no-op); otherwise `Cargo.lock` is tried first, and only
+/// when that has no entry for the crate -- and `offline` (the `--no-fetch` flag) isn't set -- do
+/// we fall back to asking crates.io for the latest published version.
+fn resolve_version(dep: &Dependency,
+                   lockfile: Option<&Manifest>,
+                   offline: bool)
+                   -> Result<Dependency, Box<Error>> {
+    if !needs_version(dep) {
+        return Ok(dep.clone());
+    }
+
+    if let Some(version) = lockfile.and_then(|lock| lock.find_locked_version(&dep.0)) {
+        return Ok((dep.0.clone(), toml::Value::String(caret_requirement(&version))));
+    }
+
+    if offline {
+        return Ok(dep.clone());
+    }
+
+    let requirement = try!(registry::fetch_latest_version(&dep.0));
+    Ok((dep.0.clone(), toml::Value::String(requirement)))
+}
+
+/// Convert a byte offset into `input` into a 0-indexed (line, column) pair.
+fn line_col(input: &str, offset: usize) -> (usize, usize) {
+    let mut line = 0;
+    let mut col = 0;
+
+    // Walk by char, stopping once we reach `offset`, rather than slicing `input` at it: `offset`
+    // comes from the TOML parser's byte count and isn't guaranteed to land on a char boundary,
+    // which would make `input[..offset]` panic on non-ASCII manifests.
+    for (idx, ch) in input.char_indices() {
+        if idx >= offset {
+            break;
+        }
+        if ch == '\n' {
+            line += 1;
+            col = 0;
+        } else {
+            col += 1;
+        }
+    }
+
+    (line, col)
 }
 
 impl str::FromStr for Manifest {
-    type Err = Box<Error>;
+    type Err = CargoEditError;
 
     /// Read manifest data from string
     fn from_str(input: &str) -> Result<Self, Self::Err> {
-        let mut parser = toml::Parser::new(&input);
+        let mut parser = toml::Parser::new(input);
 
-        parser.parse()
-              .ok_or(parser.errors.pop())
-              .map_err(Option::unwrap)
-              .map_err(From::from)
-              .map(|data| Manifest { data: data })
+        match parser.parse() {
+            Some(data) => Ok(Manifest { data: data, raw: input.to_owned() }),
+            None => {
+                let err = parser.errors.pop().expect("a failed parse always has an error");
+                let (line, col) = line_col(input, err.lo);
+
+                Err(CargoEditError::InvalidToml {
+                    line: line,
+                    col: col,
+                    msg: err.desc,
+                })
+            }
+        }
     }
 }
@@ -0,0 +1,73 @@
+use std::path::PathBuf;
+use std::process::Command;
+use rustc_serialize::json::Json;
+
+use errors::CargoEditError;
+
+fn run_cargo_metadata() -> Result<Json, CargoEditError> {
+    let metadata_failed = |msg: String| CargoEditError::MetadataFailed { msg: msg };
+
+    let output = try!(Command::new("cargo")
+                           .arg("metadata")
+                           .arg("--format-version")
+                           .arg("1")
+                           .output()
+                           .map_err(|e| metadata_failed(e.to_string())));
+
+    if !output.status.success() {
+        return Err(metadata_failed(String::from_utf8_lossy(&output.stderr).into_owned()));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    Json::from_str(&stdout).map_err(|e| metadata_failed(e.to_string()))
+}
+
+/// The `(name, manifest_path)` pairs of every package in the workspace.
+fn packages(metadata: &Json) -> Vec<(&str, &str)> {
+    let packages = match metadata.find("packages").and_then(Json::as_array) {
+        Some(packages) => packages,
+        None => return Vec::new(),
+    };
+
+    // `packages` also lists every dependency in the graph; keep only the workspace's own members.
+    let members = metadata.find("workspace_members").and_then(Json::as_array);
+    let is_member = |package: &Json| match (members, package.find("id").and_then(Json::as_string)) {
+        (Some(members), Some(id)) => members.iter().any(|member| member.as_string() == Some(id)),
+        _ => false,
+    };
+
+    packages.iter()
+            .filter(|package| is_member(package))
+            .filter_map(|package| {
+                let name = package.find("name").and_then(Json::as_string);
+                let manifest_path = package.find("manifest_path").and_then(Json::as_string);
+
+                match (name, manifest_path) {
+                    (Some(name), Some(manifest_path)) => Some((name, manifest_path)),
+                    _ => None,
+                }
+            })
+            .collect()
+}
+
+/// Map a workspace member's package name to the path of its `Cargo.toml`, via `cargo metadata`.
+pub fn locate_package(name: &str) -> Result<PathBuf, CargoEditError> {
+    let metadata = try!(run_cargo_metadata());
+    let packages = packages(&metadata);
+
+    match packages.iter().find(|&&(pkg_name, _)| pkg_name == name) {
+        Some(&(_, manifest_path)) => Ok(PathBuf::from(manifest_path)),
+        None => {
+            Err(CargoEditError::PackageNotFound {
+                name: name.to_owned(),
+                available: packages.iter().map(|&(pkg_name, _)| pkg_name.to_owned()).collect(),
+            })
+        }
+    }
+}
+
+/// The package names that make up the current workspace.
+pub fn workspace_members() -> Result<Vec<String>, CargoEditError> {
+    let metadata = try!(run_cargo_metadata());
+    Ok(packages(&metadata).iter().map(|&(name, _)| name.to_owned()).collect())
+}
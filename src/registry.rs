@@ -0,0 +1,45 @@
+use std::io::Read;
+use hyper::Client;
+use hyper::status::StatusCode;
+use rustc_serialize::json::Json;
+
+use errors::CargoEditError;
+
+/// Ask `crates.io` for the latest published, non-yanked version of `name`.
+///
+/// This is what `cargo add foo` falls back to when no version was given on the command line and
+/// the crate couldn't be resolved from `Cargo.lock` (and the caller didn't pass `--no-fetch`).
+/// Returns the requirement as a caret version (`^1.2.3`), ready to be written into the
+/// `Dependency` tuple that `insert_into_table` takes.
+pub fn fetch_latest_version(name: &str) -> Result<String, CargoEditError> {
+    let url = format!("https://crates.io/api/v1/crates/{}", name);
+    let client = Client::new();
+
+    let fetch_failed = |msg: String| {
+        CargoEditError::RegistryFetchFailed { name: name.to_owned(), msg: msg }
+    };
+
+    let mut res = try!(client.get(&url)
+                             .send()
+                             .map_err(|e| fetch_failed(e.to_string())));
+
+    if res.status == StatusCode::NotFound {
+        return Err(CargoEditError::CrateNotFound { name: name.to_owned() });
+    }
+    if !res.status.is_success() {
+        return Err(fetch_failed(format!("unexpected status {}", res.status)));
+    }
+
+    let mut body = String::new();
+    try!(res.read_to_string(&mut body).map_err(|e| fetch_failed(e.to_string())));
+
+    let json = try!(Json::from_str(&body).map_err(|e| fetch_failed(e.to_string())));
+
+    let max_version = json.find_path(&["crate", "max_version"])
+                           .and_then(Json::as_string);
+
+    max_version.map(|version| format!("^{}", version))
+               .ok_or_else(|| {
+                   fetch_failed("response had no `crate.max_version` field".to_owned())
+               })
+}